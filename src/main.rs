@@ -1,11 +1,14 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate failure;
 
-use std::collections::{HashSet, HashMap};
+use std::collections::HashMap;
+use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 use std::f64;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::thread;
 use std::thread::sleep;
 use ex::fs::{read_to_string, write};
 
@@ -13,7 +16,10 @@ use byte_unit::Byte;
 use clap::Clap;
 use env_logger as logger;
 use failure::Error;
-use walkdir::WalkDir;
+use glob::Pattern;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tiny_http::{Header, Response, Server};
 
 #[derive(Clap)]
 #[clap(version = "0.0.1", author = "Paul Linchpiner <paul@linchpiner.com>")]
@@ -30,39 +36,193 @@ struct Opts {
     /// The minimum time to wait between forcing page reclaim
     #[clap(long, default_value = "30")]
     cooldown: u64,
+    /// Which cgroup hierarchy to use: auto, v1, or v2
+    #[clap(long, default_value = "auto")]
+    cgroup_version: String,
+    /// How to render byte counts in logs: metric (base 1000), binary (base 1024), or bytes
+    #[clap(long, default_value = "binary")]
+    byte_format: String,
+    /// Number of cgroups to scan/reclaim concurrently, 0 for number of CPUs
+    #[clap(long, default_value = "0")]
+    concurrency: usize,
+    /// Address to serve Prometheus-format reclaim metrics on, e.g. 0.0.0.0:9898
+    /// (the /metrics endpoint is disabled unless this is set)
+    #[clap(long)]
+    metrics_addr: Option<String>,
+    /// Path to a rule file with one "<cgroup-path-glob> <threshold> [cooldown]" rule
+    /// per line, applied in order; the first matching rule wins, falling back to
+    /// --threshold/--cooldown for cgroups no rule matches
+    #[clap(long)]
+    config: Option<String>,
 }
 
-// Return all of the directories that are in the specified root and do not contain other
-// directories.
-fn get_dir_leaves(root: &PathBuf) -> Vec<PathBuf> {
+// A directory's immediate subdirectory listing and the mtime it was read at, so the
+// next scan can skip re-reading a directory whose own mtime hasn't changed. This
+// only ever short-circuits the `read_dir` of the cached directory itself — its
+// children are always recursed into, since a directory's mtime only reflects
+// changes to its own direct entries, not to anything deeper in the tree.
+struct DirCacheEntry {
+    mtime: SystemTime,
+    ambiguous: bool,
+    sub_dirs: Vec<PathBuf>,
+}
+
+// Whether `a` and `b` fall within the same whole second: mtime is second-granular,
+// so a write within the same second as a previous read may not move it at all, and
+// such a reading can't be trusted as a stable cache key.
+fn same_second(a: SystemTime, b: SystemTime) -> bool {
+    match (a.duration_since(UNIX_EPOCH), b.duration_since(UNIX_EPOCH)) {
+        (Ok(a), Ok(b)) => a.as_secs() == b.as_secs(),
+        _ => true,
+    }
+}
+
+fn sub_dirs(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.path())
+        .collect()
+}
+
+// Return all of the directories that are in the specified root and do not contain
+// other directories, reusing `cache` to skip re-reading (not re-recursing into) a
+// directory whose mtime hasn't changed since the last scan.
+fn get_dir_leaves(root: &PathBuf, cache: &mut HashMap<PathBuf, DirCacheEntry>, scan_time: SystemTime) -> Vec<PathBuf> {
+    let mtime = fs::metadata(root).and_then(|m| m.modified()).ok();
+
+    let cached = cache.get(root);
+    let cache_hit = match (mtime, cached) {
+        (Some(mtime), Some(cached)) => !cached.ambiguous && cached.mtime == mtime,
+        _ => false,
+    };
+
+    let sub_dirs = if cache_hit {
+        cached.unwrap().sub_dirs.clone()
+    } else {
+        sub_dirs(root)
+    };
+
+    if let Some(mtime) = mtime {
+        let ambiguous = same_second(mtime, scan_time);
+        cache.insert(root.clone(), DirCacheEntry { mtime, ambiguous, sub_dirs: sub_dirs.clone() });
+    }
+
+    if sub_dirs.is_empty() {
+        return vec![root.clone()];
+    }
+
     let mut leaves = Vec::new();
-    let mut dirs = HashSet::new();
-    let walker = WalkDir::new(root).contents_first(true);
-    let walker = walker.into_iter().filter_entry(|e| e.path().is_dir());
-    let walker = walker.filter_map(|e| e.ok());
-    for entry in walker {
-        let path = entry.into_path();
-        if dirs.contains(&path) {
-            continue
-        }
-        leaves.push(path.clone());
-        for ancestor in path.ancestors() {
-            dirs.insert(ancestor.to_path_buf());
-        }
+    for sub_dir in &sub_dirs {
+        leaves.extend(get_dir_leaves(sub_dir, cache, scan_time));
     }
+
     leaves
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Threshold {
     Bytes(u64),
     Percent(f64),
 }
 
+// One line of a `--config` rule file: the first rule whose glob matches a cgroup
+// path wins. `cooldown` falls back to the CLI default when unset.
+struct ThresholdRule {
+    pattern: Pattern,
+    threshold: Threshold,
+    cooldown: Option<u64>,
+}
+
+#[derive(Default)]
+struct Matcher {
+    rules: Vec<ThresholdRule>,
+}
+
+impl Matcher {
+    fn resolve(&self, path: &Path) -> Option<&ThresholdRule> {
+        let path = path.to_string_lossy();
+        self.rules.iter().find(|rule| rule.pattern.matches(&path))
+    }
+}
+
+// Parses a rule file where each non-empty, non-comment line is
+// "<cgroup-path-glob> <threshold> [cooldown-seconds]".
+fn load_matcher(path: &Path) -> Result<Matcher, Error> {
+    let contents = read_to_string(path)?;
+    let mut rules = Vec::new();
+
+    for (line_no, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let pattern = fields.next()
+            .ok_or_else(|| format_err!("{}:{}: missing glob pattern", path.display(), line_no + 1))?;
+        let threshold = fields.next()
+            .ok_or_else(|| format_err!("{}:{}: missing threshold", path.display(), line_no + 1))?;
+        let cooldown = fields.next()
+            .map(|value| value.parse::<u64>())
+            .transpose()
+            .map_err(|e| format_err!("{}:{}: invalid cooldown: {}", path.display(), line_no + 1, e))?;
+
+        rules.push(ThresholdRule {
+            pattern: Pattern::new(pattern)
+                .map_err(|e| format_err!("{}:{}: invalid glob pattern: {}", path.display(), line_no + 1, e))?,
+            threshold: get_threshold(threshold)?,
+            cooldown,
+        });
+    }
+
+    Ok(Matcher { rules })
+}
+
+// Which cgroup hierarchy a given cgroup path speaks. `--cgroup-version auto` leaves
+// the version unset (`None`), which defers the decision to
+// `detect_cgroup_version`, which is re-run for every cgroup since a single host can
+// have both hierarchies mounted at once (e.g. during a v1-to-v2 migration).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CgroupVersion {
+    V1,
+    V2,
+}
+
+// Mirrors byte_unit's base-1000 (Metric) vs base-1024 (Binary) distinction, plus a
+// raw passthrough for scripting/log-grepping use cases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteFormat {
+    Metric,
+    Binary,
+    Bytes,
+}
+
+#[derive(Clone, Default)]
 struct ReclaimState {
     last_seen: Option<Instant>,
     last_reclaimed: Option<Instant>,
     last_error: Option<Instant>,
+    // Metrics accumulated for as long as this cgroup stays known, served over
+    // `--metrics-addr`.
+    reclaim_count: u64,
+    reclaim_errors: u64,
+    bytes_freed: u64,
+    last_stats: Option<MemoryStats>,
+}
+
+// `states` is read by the metrics server thread and written by the reclaim loop.
+type SharedStates = Arc<Mutex<HashMap<PathBuf, ReclaimState>>>;
+
+// The result of scanning/reclaiming a single cgroup on a worker thread, merged back
+// into the shared `states` map single-threadedly once the parallel phase completes.
+struct ReclaimOutcome {
+    result: Result<(), Error>,
+    state: ReclaimState,
 }
 
 struct ReclaimLoop {
@@ -70,9 +230,14 @@ struct ReclaimLoop {
     threshold: Threshold,
     interval: u64,
     cooldown: u64,
+    cgroup_version: Option<CgroupVersion>,
+    byte_format: ByteFormat,
+    concurrency: usize,
+    metrics_addr: Option<String>,
+    matcher: Matcher,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MemoryStats {
     pub limit: u64,
     pub cache: u64,
@@ -82,17 +247,34 @@ struct MemoryStats {
 impl ReclaimLoop {
     fn start(&self) {
         info!("Parent: {}", &self.parent.display());
-        info!("Threshold: {:?}, interval: {}s, cooldown: {}s",
+        info!("Threshold: {:?}, interval: {}s, cooldown: {}s, concurrency: {}",
             self.threshold,
             self.interval,
-            self.cooldown);
+            self.cooldown,
+            self.concurrency);
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.concurrency)
+            .build()
+            .unwrap_or_else(|err| {
+                warn!("Failed to build a pool with {} threads, falling back to the default: {}",
+                    self.concurrency, err);
+                ThreadPoolBuilder::new().build().expect("default rayon pool")
+            });
+
+        let states: SharedStates = Arc::new(Mutex::new(HashMap::new()));
+        if let Some(addr) = &self.metrics_addr {
+            let addr = addr.clone();
+            let states = Arc::clone(&states);
+            thread::spawn(move || serve_metrics(&addr, &states));
+        }
 
         let interval_ms = 1000u128 * self.interval as u128;
-        let mut states = HashMap::new();
+        let mut discovery_cache = HashMap::new();
         loop {
             let now = Instant::now();
-            self.reclaim(&mut states);
-            self.cleanup(&now, &mut states);
+            self.reclaim(&pool, &states, &mut discovery_cache);
+            self.cleanup(&now, &states);
             let elapsed = now.elapsed().as_millis();
             if elapsed > interval_ms {
                 warn!("Reclaim loop took {}ms, longer than interval {}ms", elapsed, interval_ms);
@@ -104,21 +286,36 @@ impl ReclaimLoop {
         }
     }
 
-    fn reclaim(&self, states: &mut HashMap<PathBuf, ReclaimState>) {
-        let cgroups = get_dir_leaves(&self.parent);
-        for cgroup in &cgroups {
+    fn reclaim(
+        &self,
+        pool: &ThreadPool,
+        states: &SharedStates,
+        discovery_cache: &mut HashMap<PathBuf, DirCacheEntry>,
+    ) {
+        let cgroups = get_dir_leaves(&self.parent, discovery_cache, SystemTime::now());
+        let now = Some(Instant::now());
+
+        // Each worker reads its own copy of the previous state (cooldown/last-error
+        // tracking only needs last-reclaimed/last-error, both of which are plain
+        // Instants), so cgroups can be scanned/reclaimed concurrently without
+        // synchronizing the shared `states` map until the merge below.
+        let snapshot = states.lock().unwrap().clone();
+        let outcomes: Vec<(PathBuf, ReclaimOutcome)> = pool.install(|| {
+            cgroups.par_iter().map(|cgroup| {
+                let mut state = snapshot.get(cgroup).cloned().unwrap_or_default();
+                let result = self.reclaim_cgroup(cgroup, &mut state);
+                (cgroup.clone(), ReclaimOutcome { result, state })
+            }).collect()
+        });
 
-            let state = states.entry(cgroup.clone()).or_insert_with(|| {
+        let mut states = states.lock().unwrap();
+        for (cgroup, outcome) in outcomes {
+            if !states.contains_key(&cgroup) {
                 info!("New cgroup: {}", cgroup.display());
-                ReclaimState {
-                    last_seen: None,
-                    last_reclaimed: None,
-                    last_error: None,
-                }
-            });
+            }
 
-            let now = Some(Instant::now());
-            match self.reclaim_cgroup(cgroup, state) {
+            let mut state = outcome.state;
+            match outcome.result {
                 Ok(()) => {
                     state.last_error = None;
                 },
@@ -127,31 +324,41 @@ impl ReclaimLoop {
                         warn!("Failed to reclaim {}: {}", cgroup.display(), err);
                     }
                     state.last_error = now;
+                    state.reclaim_errors += 1;
                 }
             };
             state.last_seen = now;
+            states.insert(cgroup, state);
         }
     }
 
     fn reclaim_cgroup(&self, path: &Path, state: &mut ReclaimState) -> Result<(), Error> {
-        let stats = &get_memory_stats(path)?;
-        if self.can_be_reclaimed(stats, state) {
+        let version = self.cgroup_version.unwrap_or_else(|| detect_cgroup_version(path));
+        let stats = get_memory_stats(path, version)?;
+        let rule = self.matcher.resolve(path);
+        if self.can_be_reclaimed(rule, &stats, state) {
             let display = path.display();
-            info!("Reclaiming {}: {:?}", display, stats);
-            reclaim(path)?;
+            info!("Reclaiming {}: {}", display, format_memory_stats(&stats, self.byte_format));
+            reclaim(path, version, &stats)?;
             state.last_reclaimed = Some(Instant::now());
-            let stats_after = &get_memory_stats(path)?;
-            info!("Reclaimed  {}: {:?}", display, stats_after);
+            state.reclaim_count += 1;
+            let stats_after = get_memory_stats(path, version)?;
+            state.bytes_freed += stats.cache.saturating_sub(stats_after.cache);
+            info!("Reclaimed  {}: {}", display, format_memory_stats(&stats_after, self.byte_format));
+            state.last_stats = Some(stats_after);
+        } else {
+            state.last_stats = Some(stats);
         }
         Ok(())
     }
 
-    fn can_be_reclaimed(&self, stats: &MemoryStats, state: &ReclaimState) -> bool {
-        if self.needs_to_be_reclaimed(stats) {
+    fn can_be_reclaimed(&self, rule: Option<&ThresholdRule>, stats: &MemoryStats, state: &ReclaimState) -> bool {
+        if self.needs_to_be_reclaimed(rule, stats) {
+            let cooldown = rule.and_then(|rule| rule.cooldown).unwrap_or(self.cooldown);
             let now = Instant::now();
             return match state.last_reclaimed {
                 Some(last_reclaimed) => {
-                    now.duration_since(last_reclaimed).as_secs() > self.cooldown
+                    now.duration_since(last_reclaimed).as_secs() > cooldown
                 },
                 None => true,
             }
@@ -159,8 +366,9 @@ impl ReclaimLoop {
         false
     }
 
-    fn needs_to_be_reclaimed(&self, stats: &MemoryStats) -> bool {
-        match self.threshold {
+    fn needs_to_be_reclaimed(&self, rule: Option<&ThresholdRule>, stats: &MemoryStats) -> bool {
+        let threshold = rule.map(|rule| rule.threshold).unwrap_or(self.threshold);
+        match threshold {
             Threshold::Bytes(threshold) => {
                 stats.cache >= threshold
             },
@@ -170,8 +378,8 @@ impl ReclaimLoop {
         }
     }
 
-    fn cleanup(&self, now: &Instant, states: &mut HashMap<PathBuf, ReclaimState>) {
-        states.retain(|cgroup, state| {
+    fn cleanup(&self, now: &Instant, states: &SharedStates) {
+        states.lock().unwrap().retain(|cgroup, state| {
             if let Some(last_seen) = state.last_seen {
                 if last_seen  >= *now {
                     return true;
@@ -183,7 +391,24 @@ impl ReclaimLoop {
     }
 }
 
-fn get_memory_stats(path: &Path) -> Result<MemoryStats, Error> {
+// cgroup v2 hosts mount the unified hierarchy, which exposes `cgroup.controllers` in
+// every cgroup directory and `memory.current` instead of v1's `memory.limit_in_bytes`.
+fn detect_cgroup_version(path: &Path) -> CgroupVersion {
+    if path.join("memory.current").is_file() || path.join("cgroup.controllers").is_file() {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::V1
+    }
+}
+
+fn get_memory_stats(path: &Path, version: CgroupVersion) -> Result<MemoryStats, Error> {
+    match version {
+        CgroupVersion::V1 => get_memory_stats_v1(path),
+        CgroupVersion::V2 => get_memory_stats_v2(path),
+    }
+}
+
+fn get_memory_stats_v1(path: &Path) -> Result<MemoryStats, Error> {
     let limit_path = path.to_path_buf().join("memory.limit_in_bytes");
     let stats_path = path.to_path_buf().join("memory.stat");
 
@@ -213,6 +438,61 @@ fn get_memory_stats(path: &Path) -> Result<MemoryStats, Error> {
     })
 }
 
+// v2's `memory.stat` uses `file`/`anon` in place of v1's `cache`/`rss`, and the limit
+// lives in `memory.max`, which reads "max" for an unbounded cgroup.
+fn get_memory_stats_v2(path: &Path) -> Result<MemoryStats, Error> {
+    let max_path = path.to_path_buf().join("memory.max");
+    let stats_path = path.to_path_buf().join("memory.stat");
+
+    let mut rss: Option<u64> = None;
+    let mut cache: Option<u64> = None;
+
+    let string = read_to_string(&stats_path)?;
+    for line in string.lines() {
+        if rss.is_none() {
+            rss = parse_u64_strip_prefix("anon ", line);
+        }
+        if cache.is_none() {
+            cache = parse_u64_strip_prefix("file ", line);
+        }
+        if rss.is_some() && cache.is_some() {
+            break;
+        }
+    }
+
+    let string = read_to_string(max_path)?;
+    let limit: Option<u64> = match string.trim() {
+        "max" => Some(u64::max_value()),
+        value => value.parse().ok(),
+    };
+
+    Ok(MemoryStats {
+        rss: rss.unwrap_or_default(),
+        cache: cache.unwrap_or_default(),
+        limit: limit.unwrap_or_default(),
+    })
+}
+
+fn format_byte_count(value: u64, format: ByteFormat) -> String {
+    let adjusted = match format {
+        ByteFormat::Bytes => return value.to_string(),
+        ByteFormat::Metric => Byte::from_bytes(value as u128).get_appropriate_unit(false),
+        ByteFormat::Binary => Byte::from_bytes(value as u128).get_appropriate_unit(true),
+    };
+    // byte_unit always prints two fractional digits; drop them when both are zero,
+    // so a whole count reads "100 MiB" rather than "100.00 MiB".
+    adjusted.to_string().replacen(".00 ", " ", 1)
+}
+
+fn format_memory_stats(stats: &MemoryStats, format: ByteFormat) -> String {
+    format!(
+        "MemoryStats {{ limit: {}, cache: {}, rss: {} }}",
+        format_byte_count(stats.limit, format),
+        format_byte_count(stats.cache, format),
+        format_byte_count(stats.rss, format),
+    )
+}
+
 fn parse_u64_strip_prefix(prefix: &str, line: &str) -> Option<u64> {
     let line = line.trim();
     if line.starts_with(prefix) {
@@ -221,11 +501,110 @@ fn parse_u64_strip_prefix(prefix: &str, line: &str) -> Option<u64> {
     None
 }
 
-fn reclaim(path: &Path) -> Result<(), Error> {
+fn reclaim(path: &Path, version: CgroupVersion, stats: &MemoryStats) -> Result<(), Error> {
+    match version {
+        CgroupVersion::V1 => reclaim_v1(path),
+        CgroupVersion::V2 => reclaim_v2(path, stats),
+    }
+}
+
+fn reclaim_v1(path: &Path) -> Result<(), Error> {
     let force_empty_path = path.to_path_buf().join("memory.force_empty");
     Ok(write(force_empty_path, "1")?)
 }
 
+// v2 has no `force_empty` knob; instead, a byte count written to `memory.reclaim`
+// tells the kernel how much page cache to try to reclaim from this cgroup.
+fn reclaim_v2(path: &Path, stats: &MemoryStats) -> Result<(), Error> {
+    let reclaim_path = path.to_path_buf().join("memory.reclaim");
+    Ok(write(reclaim_path, stats.cache.to_string())?)
+}
+
+// Serves `states` as Prometheus text exposition format on every request to any
+// path; the request path/method aren't inspected since this is a single-purpose
+// metrics endpoint.
+fn serve_metrics(addr: &str, states: &SharedStates) {
+    let server = match Server::http(addr) {
+        Ok(server) => server,
+        Err(err) => {
+            warn!("Failed to start metrics server on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+        .expect("valid header");
+
+    for request in server.incoming_requests() {
+        let body = render_prometheus_metrics(&states.lock().unwrap());
+        let response = Response::from_string(body).with_header(content_type.clone());
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to write metrics response: {}", err);
+        }
+    }
+}
+
+fn render_prometheus_metrics(states: &HashMap<PathBuf, ReclaimState>) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP cgroup_memory_manager_reclaim_total Reclaim invocations per cgroup.\n");
+    out.push_str("# TYPE cgroup_memory_manager_reclaim_total counter\n");
+    for (cgroup, state) in states {
+        out.push_str(&format!(
+            "cgroup_memory_manager_reclaim_total{{cgroup=\"{}\"}} {}\n",
+            escape_label(cgroup), state.reclaim_count));
+    }
+
+    out.push_str("# HELP cgroup_memory_manager_reclaim_errors_total Reclaim errors per cgroup.\n");
+    out.push_str("# TYPE cgroup_memory_manager_reclaim_errors_total counter\n");
+    for (cgroup, state) in states {
+        out.push_str(&format!(
+            "cgroup_memory_manager_reclaim_errors_total{{cgroup=\"{}\"}} {}\n",
+            escape_label(cgroup), state.reclaim_errors));
+    }
+
+    out.push_str("# HELP cgroup_memory_manager_bytes_freed_total Page cache bytes freed per cgroup.\n");
+    out.push_str("# TYPE cgroup_memory_manager_bytes_freed_total counter\n");
+    for (cgroup, state) in states {
+        out.push_str(&format!(
+            "cgroup_memory_manager_bytes_freed_total{{cgroup=\"{}\"}} {}\n",
+            escape_label(cgroup), state.bytes_freed));
+    }
+
+    render_gauge(&mut out, "cache_bytes", states, |s| s.cache);
+    render_gauge(&mut out, "rss_bytes", states, |s| s.rss);
+    render_gauge(&mut out, "limit_bytes", states, |s| s.limit);
+
+    out.push_str("# HELP cgroup_memory_manager_reclaim_total_aggregate Reclaim invocations across all cgroups.\n");
+    out.push_str("# TYPE cgroup_memory_manager_reclaim_total_aggregate counter\n");
+    out.push_str(&format!("cgroup_memory_manager_reclaim_total_aggregate {}\n",
+        states.values().map(|s| s.reclaim_count).sum::<u64>()));
+
+    out.push_str("# HELP cgroup_memory_manager_bytes_freed_total_aggregate Page cache bytes freed across all cgroups.\n");
+    out.push_str("# TYPE cgroup_memory_manager_bytes_freed_total_aggregate counter\n");
+    out.push_str(&format!("cgroup_memory_manager_bytes_freed_total_aggregate {}\n",
+        states.values().map(|s| s.bytes_freed).sum::<u64>()));
+
+    out
+}
+
+fn render_gauge(out: &mut String, metric: &str, states: &HashMap<PathBuf, ReclaimState>, select: fn(&MemoryStats) -> u64) {
+    out.push_str(&format!("# HELP cgroup_memory_manager_{} Last observed memory.{}.\n", metric, metric));
+    out.push_str(&format!("# TYPE cgroup_memory_manager_{} gauge\n", metric));
+    for (cgroup, state) in states {
+        if let Some(stats) = &state.last_stats {
+            out.push_str(&format!(
+                "cgroup_memory_manager_{}{{cgroup=\"{}\"}} {}\n",
+                metric, escape_label(cgroup), select(stats)));
+        }
+    }
+}
+
+fn escape_label(path: &Path) -> String {
+    path.display().to_string().replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 fn get_parent(value: &str) -> Result<PathBuf, Error> {
     // Check the specified parent exists
     let parent = Path::new(value);
@@ -250,6 +629,33 @@ fn get_threshold(value: &str) -> Result<Threshold, Error> {
     }
 }
 
+fn get_cgroup_version(value: &str) -> Result<Option<CgroupVersion>, Error> {
+    match value {
+        "auto" => Ok(None),
+        "v1" => Ok(Some(CgroupVersion::V1)),
+        "v2" => Ok(Some(CgroupVersion::V2)),
+        _ => Err(format_err!("Invalid cgroup version: '{}', expected auto, v1, or v2", value)),
+    }
+}
+
+// 0 means "use all available CPUs"; anything else is taken as an explicit cap.
+fn resolve_concurrency(value: usize) -> usize {
+    if value > 0 {
+        value
+    } else {
+        std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+}
+
+fn get_byte_format(value: &str) -> Result<ByteFormat, Error> {
+    match value {
+        "metric" => Ok(ByteFormat::Metric),
+        "binary" => Ok(ByteFormat::Binary),
+        "bytes" => Ok(ByteFormat::Bytes),
+        _ => Err(format_err!("Invalid byte format: '{}', expected metric, binary, or bytes", value)),
+    }
+}
+
 fn main() -> Result<(), Error> {
     logger::init();
     let opts: Opts = Opts::parse();
@@ -259,6 +665,14 @@ fn main() -> Result<(), Error> {
         interval: opts.interval,
         cooldown: opts.cooldown,
         threshold: get_threshold(&opts.threshold)?,
+        cgroup_version: get_cgroup_version(&opts.cgroup_version)?,
+        byte_format: get_byte_format(&opts.byte_format)?,
+        concurrency: resolve_concurrency(opts.concurrency),
+        metrics_addr: opts.metrics_addr,
+        matcher: match &opts.config {
+            Some(path) => load_matcher(Path::new(path))?,
+            None => Matcher::default(),
+        },
     }.start();
 
     Ok(())
@@ -266,11 +680,19 @@ fn main() -> Result<(), Error> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{get_threshold, Threshold, ReclaimLoop, ReclaimState};
+    use crate::{
+        get_threshold, get_cgroup_version, get_byte_format, format_byte_count, resolve_concurrency,
+        same_second, get_dir_leaves, render_prometheus_metrics, ByteFormat, CgroupVersion, Matcher,
+        MemoryStats, Threshold, ThresholdRule, ReclaimLoop, ReclaimState,
+    };
+    use glob::Pattern;
     use std::collections::HashMap;
-    use std::time::Instant;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Instant, SystemTime};
     use failure::_core::time::Duration;
     use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+    use std::{fs, thread};
 
     #[test]
     fn test_threshold() {
@@ -307,6 +729,115 @@ mod tests {
         assert_eq!(threshold, Threshold::Bytes(107_374_182_400));
     }
 
+    #[test]
+    fn test_cgroup_version() {
+        assert_eq!(get_cgroup_version("auto").unwrap(), None);
+        assert_eq!(get_cgroup_version("v1").unwrap(), Some(CgroupVersion::V1));
+        assert_eq!(get_cgroup_version("v2").unwrap(), Some(CgroupVersion::V2));
+        assert!(get_cgroup_version("v3").is_err());
+    }
+
+    #[test]
+    fn test_byte_format() {
+        assert_eq!(get_byte_format("metric").unwrap(), ByteFormat::Metric);
+        assert_eq!(get_byte_format("binary").unwrap(), ByteFormat::Binary);
+        assert_eq!(get_byte_format("bytes").unwrap(), ByteFormat::Bytes);
+        assert!(get_byte_format("nope").is_err());
+
+        assert_eq!(format_byte_count(104_857_600, ByteFormat::Bytes), "104857600");
+        assert_eq!(format_byte_count(104_857_600, ByteFormat::Binary), "100 MiB");
+        assert_eq!(format_byte_count(100_000_000, ByteFormat::Metric), "100 MB");
+    }
+
+    #[test]
+    fn test_same_second() {
+        let base = UNIX_EPOCH + Duration::from_millis(1_000_500);
+        let same_second_later = UNIX_EPOCH + Duration::from_millis(1_000_900);
+        let next_second = UNIX_EPOCH + Duration::from_millis(1_001_500);
+
+        assert!(same_second(base, same_second_later));
+        assert!(!same_second(base, next_second));
+    }
+
+    #[test]
+    fn test_get_dir_leaves_finds_new_nested_leaf_after_cache() {
+        let root = std::env::temp_dir().join(format!("cgroup-memory-manager-test-{:?}", std::thread::current().id()));
+        let a = root.join("A");
+        let a1 = a.join("A1");
+        fs::create_dir_all(&a1).unwrap();
+
+        let mut cache = HashMap::new();
+        let first = get_dir_leaves(&root, &mut cache, SystemTime::now());
+        assert_eq!(first, vec![a1.clone()]);
+
+        // Let `root` and `A`'s mtimes settle into an unambiguous, cacheable past second
+        // before adding a sibling leaf two levels below `root`.
+        thread::sleep(Duration::from_millis(1_100));
+        let a2 = a.join("A2");
+        fs::create_dir_all(&a2).unwrap();
+
+        let mut second = get_dir_leaves(&root, &mut cache, SystemTime::now());
+        fs::remove_dir_all(&root).unwrap();
+
+        second.sort();
+        let mut expected = vec![a1, a2];
+        expected.sort();
+        assert_eq!(second, expected);
+    }
+
+    #[test]
+    fn test_resolve_concurrency() {
+        assert_eq!(resolve_concurrency(4), 4);
+        assert!(resolve_concurrency(0) >= 1);
+    }
+
+    #[test]
+    fn test_matcher_resolve() {
+        let matcher = Matcher {
+            rules: vec![
+                ThresholdRule {
+                    pattern: Pattern::new("*/database-*").unwrap(),
+                    threshold: Threshold::Bytes(209_715_200),
+                    cooldown: Some(60),
+                },
+                ThresholdRule {
+                    pattern: Pattern::new("*").unwrap(),
+                    threshold: Threshold::Percent(10f64),
+                    cooldown: None,
+                },
+            ],
+        };
+
+        let database_rule = matcher.resolve(&PathBuf::from("/sys/fs/cgroup/memory/docker/database-1")).unwrap();
+        assert_eq!(database_rule.threshold, Threshold::Bytes(209_715_200));
+        assert_eq!(database_rule.cooldown, Some(60));
+
+        let fallback_rule = matcher.resolve(&PathBuf::from("/sys/fs/cgroup/memory/docker/web-1")).unwrap();
+        assert_eq!(fallback_rule.threshold, Threshold::Percent(10f64));
+
+        assert!(Matcher::default().resolve(&PathBuf::from("/anything")).is_none());
+    }
+
+    #[test]
+    fn test_render_prometheus_metrics() {
+        let mut states = HashMap::new();
+        states.insert(PathBuf::from("/sys/fs/cgroup/memory/docker/abc"), ReclaimState {
+            reclaim_count: 3,
+            reclaim_errors: 1,
+            bytes_freed: 2048,
+            last_stats: Some(MemoryStats { limit: 104_857_600, cache: 1024, rss: 512 }),
+            ..Default::default()
+        });
+
+        let text = render_prometheus_metrics(&states);
+
+        assert!(text.contains("cgroup_memory_manager_reclaim_total{cgroup=\"/sys/fs/cgroup/memory/docker/abc\"} 3"));
+        assert!(text.contains("cgroup_memory_manager_reclaim_errors_total{cgroup=\"/sys/fs/cgroup/memory/docker/abc\"} 1"));
+        assert!(text.contains("cgroup_memory_manager_bytes_freed_total{cgroup=\"/sys/fs/cgroup/memory/docker/abc\"} 2048"));
+        assert!(text.contains("cgroup_memory_manager_cache_bytes{cgroup=\"/sys/fs/cgroup/memory/docker/abc\"} 1024"));
+        assert!(text.contains("cgroup_memory_manager_reclaim_total_aggregate 3"));
+    }
+
     #[test]
     fn test_reclaim_loop_cleanup() {
         let reclaim_loop = ReclaimLoop {
@@ -314,6 +845,11 @@ mod tests {
             interval: 0,
             cooldown: 0,
             threshold: Threshold::Bytes(0),
+            cgroup_version: None,
+            byte_format: ByteFormat::Binary,
+            concurrency: 1,
+            metrics_addr: None,
+            matcher: Matcher::default(),
         };
 
         let second = Duration::from_secs(1);
@@ -323,13 +859,15 @@ mod tests {
         let mut states = HashMap::new();
 
         states.insert(PathBuf::from("never"), ReclaimState{
-            last_seen: None, last_reclaimed: None, last_error: None});
+            last_seen: None, ..Default::default()});
         states.insert(PathBuf::from("before"), ReclaimState{
-            last_seen: Some(before), last_reclaimed: None, last_error: None});
+            last_seen: Some(before), ..Default::default()});
         states.insert(PathBuf::from("after"), ReclaimState{
-            last_seen: Some(after), last_reclaimed: None, last_error: None});
+            last_seen: Some(after), ..Default::default()});
 
-        reclaim_loop.cleanup(&now, &mut states);
+        let states = Arc::new(Mutex::new(states));
+        reclaim_loop.cleanup(&now, &states);
+        let states = states.lock().unwrap();
 
         assert!(! states.contains_key(&PathBuf::from("never")));
         assert!(! states.contains_key(&PathBuf::from("before")));